@@ -0,0 +1,78 @@
+use {
+    std::{
+        fs,
+        io::{self, BufRead, Write},
+        path::PathBuf,
+    },
+    anyhow::{Result, anyhow},
+    tracing::{debug, info, Level},
+};
+
+#[derive(Debug, structopt::StructOpt)]
+struct Args {
+    #[structopt(short, long, default_value = "irctweets.toml")]
+    config: PathBuf,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct Config {
+    twitter: ConsumerConfig,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct ConsumerConfig {
+    consumer_token: String,
+    consumer_token_secret: String,
+}
+
+#[paw::main]
+#[tokio::main]
+async fn main(args: Args) -> Result<()> {
+    let subscriber = tracing_subscriber::fmt::Subscriber::builder()
+        .with_max_level(Level::INFO)
+        .compact()
+        .with_writer(io::stderr)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+    debug!(?args, "starting up");
+
+    let file_contents = fs::read_to_string(&args.config)?;
+    let config: Config = toml::from_str(&file_contents)?;
+
+    let consumer = egg_mode::KeyPair::new(
+        config.twitter.consumer_token, config.twitter.consumer_token_secret);
+
+    let request_token = egg_mode::auth::request_token(&consumer, "oob").await?;
+
+    let authorize_url = egg_mode::auth::authorize_url(&request_token);
+    println!("go to this url, authorize the app, and paste the PIN it gives you:");
+    println!("{}", authorize_url);
+    print!("PIN: ");
+    io::stdout().flush()?;
+
+    let mut pin = String::new();
+    io::stdin().lock().read_line(&mut pin)?;
+    let pin = pin.trim();
+
+    let (token, _user_id, screen_name) =
+        egg_mode::auth::access_token(consumer, &request_token, pin).await?;
+
+    let (access_token, access_token_secret) = match token {
+        egg_mode::Token::Access { access, .. } =>
+            (access.key.into_owned(), access.secret.into_owned()),
+        egg_mode::Token::Bearer(_) =>
+            return Err(anyhow!("got a bearer token back, expected an access token")),
+    };
+
+    // Edit in place with toml_edit rather than round-tripping through
+    // toml::Value, so the operator's comments and formatting survive.
+    let mut doc = file_contents.parse::<toml_edit::Document>()?;
+    doc["twitter"]["access_token"] = toml_edit::value(access_token);
+    doc["twitter"]["access_token_secret"] = toml_edit::value(access_token_secret);
+
+    fs::write(&args.config, doc.to_string())?;
+    info!(config = %args.config.display(), %screen_name,
+        "wrote access token to config");
+
+    Ok(())
+}
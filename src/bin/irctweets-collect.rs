@@ -6,13 +6,26 @@ use {
         fs,
         io,
         path::{Path, PathBuf},
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
     },
     irc::client::prelude::*,
     anyhow::{Result, anyhow},
-    tokio::{runtime::Runtime, stream::StreamExt},
+    futures::future::{BoxFuture, FutureExt},
+    rusqlite::OptionalExtension,
+    tokio::{runtime::Runtime, stream::StreamExt, time::{delay_for, Instant}},
     tracing::{trace, debug, info, error, span, Level},
 };
 
+/// How long a connection has to stay up before we forgive it and reset the
+/// reconnect backoff back to `MIN_RECONNECT_BACKOFF`.
+const SUSTAINED_CONNECTION: Duration = Duration::from_secs(5 * 60);
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long to wait before announcing the same tweet as a repost again.
+const REPOST_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Debug, structopt::StructOpt)]
 struct Args {
     #[structopt(short, long, default_value = "irctweets.toml")]
@@ -23,6 +36,10 @@ struct App {
     db: rusqlite::Connection,
     link_finder: linkify::LinkFinder,
     help_msg: String,
+    token: Option<egg_mode::Token>,
+    reconnect_requested: AtomicBool,
+    announce_reposts: bool,
+    repost_last_announced: std::sync::Mutex<std::collections::HashMap<i64, Instant>>,
 }
 
 #[derive(Debug, Clone, PartialEq, serde_derive::Deserialize)]
@@ -30,6 +47,19 @@ struct Config {
     db: PathBuf,
     help_msg: String,
     irc: irc::client::data::config::Config,
+    #[serde(default)]
+    unfurl: bool,
+    twitter: Option<TwitterConfig>,
+    #[serde(default)]
+    announce_reposts: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Deserialize)]
+struct TwitterConfig {
+    consumer_token: String,
+    consumer_token_secret: String,
+    access_token: String,
+    access_token_secret: String,
 }
 
 impl Config {
@@ -70,6 +100,33 @@ impl App {
             )
         ", rusqlite::NO_PARAMS)?;
 
+        self.add_column_if_missing("tweet", "tweet_text", "text")?;
+        self.add_column_if_missing("tweet", "author", "text")?;
+
+        Ok(())
+    }
+
+    fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        let mut stmt = self.db.prepare(&format!("pragma table_info({})", table))?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(1)?;
+            if name == column {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn add_column_if_missing(&self, table: &str, column: &str, ty: &str)
+            -> Result<()> {
+        if !self.has_column(table, column)? {
+            self.db.execute(&format!(
+                "alter table {} add column {} {}", table, column, ty
+            ), rusqlite::NO_PARAMS)?;
+        }
+
         Ok(())
     }
 
@@ -91,7 +148,7 @@ impl App {
             return Ok(());
         }
 
-        if target.starts_with('#') {
+        if !target.starts_with('#') {
             // don't retweet stuff from private messages
             return Ok(());
         }
@@ -121,13 +178,116 @@ impl App {
                 }
             };
 
-            let tweet = self.maybe_insert_tweet(tweet_id)?;
+            let (tweet, is_new) = self.maybe_insert_tweet(tweet_id)?;
             self.maybe_insert_occurence(line, tweet)?;
+
+            if !is_new {
+                if let Err(e) = self.maybe_announce_repost(client, target, tweet).await {
+                    error!(%e, %tweet_id, "couldn't announce repost");
+                }
+            }
+
+            if let Err(e) = self.maybe_unfurl(client, target, tweet, tweet_id).await {
+                error!(%e, %tweet_id, "couldn't unfurl tweet");
+            }
         }
 
         Ok(())
     }
 
+    async fn maybe_unfurl(&self, client: &Client, target: &str, tweet: i64,
+            tweet_id: u64) -> Result<()> {
+        let token = match &self.token {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        let (author, text) = match self.get_tweet_text(tweet)? {
+            Some(cached) => cached,
+            None => {
+                let status = egg_mode::tweet::show(tweet_id, token).await?.response;
+                let author = status.user.map(|u| u.screen_name)
+                    .unwrap_or_else(|| "unknown".to_owned());
+                let text = status.text.clone();
+                self.store_tweet_text(tweet, &author, &text)?;
+                (author, text)
+            }
+        };
+
+        client.send_privmsg(target, &format_preview(&author, &text))?;
+        Ok(())
+    }
+
+    async fn maybe_announce_repost(&self, client: &Client, target: &str,
+            tweet: i64) -> Result<()> {
+        if !self.announce_reposts {
+            return Ok(());
+        }
+
+        if !self.check_repost_cooldown(tweet) {
+            trace!(%tweet, "repost notice on cooldown");
+            return Ok(());
+        }
+
+        let first: Option<(String, String)> = self.db.query_row("
+            select line.prefix, line.timestamp
+            from occurence
+            join line on line.id = occurence.line
+            where occurence.tweet = ?
+            order by line.timestamp asc
+            limit 1
+        ", &[tweet], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+
+        let (prefix, timestamp) = match first {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+        let nick = prefix.split('!').next().unwrap_or(&prefix);
+
+        client.send_privmsg(target,
+            &format!("already posted by {} on {}", nick, timestamp))?;
+
+        Ok(())
+    }
+
+    /// Returns `true` (and records the announcement) if this tweet hasn't
+    /// been announced as a repost within `REPOST_COOLDOWN`.
+    fn check_repost_cooldown(&self, tweet: i64) -> bool {
+        let mut last_announced = self.repost_last_announced.lock().unwrap();
+        let now = Instant::now();
+
+        // Entries past the cooldown can never block another announcement,
+        // so drop them here instead of letting the map grow forever.
+        last_announced.retain(|_, &mut last| now.duration_since(last) < REPOST_COOLDOWN);
+
+        if last_announced.contains_key(&tweet) {
+            return false;
+        }
+
+        last_announced.insert(tweet, now);
+        true
+    }
+
+    fn get_tweet_text(&self, tweet: i64) -> Result<Option<(String, String)>> {
+        let row: Option<(Option<String>, Option<String>)> = self.db.query_row("
+            select author, tweet_text from tweet where id = ?
+        ", &[tweet], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+
+        Ok(row.and_then(|(author, text)| match (author, text) {
+            (Some(author), Some(text)) => Some((author, text)),
+            _ => None,
+        }))
+    }
+
+    fn store_tweet_text(&self, tweet: i64, author: &str, text: &str)
+            -> Result<()> {
+        self.db.execute("
+            update tweet set author = ?, tweet_text = ? where id = ?
+        ", rusqlite::params![author, text, tweet])?;
+
+        Ok(())
+    }
+
     fn insert_line(&self, prefix: &str, target: &str, msg: &str)
             -> Result<i64> {
         self.db.execute("
@@ -138,17 +298,20 @@ impl App {
         Ok(self.db.last_insert_rowid())
     }
 
-    fn maybe_insert_tweet(&self, tweet_id: u64) -> Result<i64> {
+    /// Inserts `tweet_id` if it hasn't been seen before. Returns the
+    /// tweet's row id along with whether this call is what inserted it.
+    fn maybe_insert_tweet(&self, tweet_id: u64) -> Result<(i64, bool)> {
         self.db.execute("
             insert or ignore into tweet(tweet_id)
             values(?);
         ", &[tweet_id as i64])?;
+        let is_new = self.db.changes() > 0;
 
         let tweet = self.db.query_row("
             select id from tweet where tweet_id = ?
         ", &[tweet_id as i64], |row| row.get(0))?;
 
-        Ok(tweet)
+        Ok((tweet, is_new))
     }
 
     fn maybe_insert_occurence(&self, line: i64, tweet: i64) -> Result<()> {
@@ -197,27 +360,143 @@ impl App {
         None
     }
 
-    async fn handle_command<S>(&self, client: &Client,
-            command: &ChatCommand<S>) -> Result<()>
-            where S: AsRef<str>+Debug {
+    async fn handle_command(&self, client: &Client,
+            command: &ChatCommand<&str>) -> Result<()> {
         let span =
             span!(Level::TRACE, "handle_command", ?command);
         let _enter = span.enter();
         trace!("command");
-        if command.message.as_ref() == "help" {
-            trace!("command_help");
-            let mut msg = String::new();
-            if let Some(addr) = &command.response_address {
-                write!(msg, "{}: ", addr.as_ref())?;
+
+        let mut parts = command.message.split_whitespace();
+        let keyword = match parts.next() {
+            Some(keyword) => keyword,
+            None => return Ok(()),
+        };
+        let args: Vec<&str> = parts.collect();
+
+        let cmd = match COMMANDS.iter().find(|c| c.keyword == keyword) {
+            Some(cmd) => cmd,
+            None => {
+                trace!(%keyword, "unknown command");
+                return Ok(());
             }
-            write!(msg, "{}", self.help_msg)?;
-            client.send_privmsg(command.response_target.as_ref(), &msg)?;
+        };
+
+        if args.len() < cmd.arity {
+            trace!(%keyword, arity = cmd.arity, "not enough arguments");
+            return Ok(());
         }
 
+        (cmd.exec)(self, client, command, &args).await
+    }
+
+    fn reply(&self, client: &Client, command: &ChatCommand<&str>,
+            text: &str) -> Result<()> {
+        let mut msg = String::new();
+        if let Some(addr) = command.response_address {
+            write!(msg, "{}: ", addr)?;
+        }
+        write!(msg, "{}", text)?;
+        client.send_privmsg(command.response_target, &msg)?;
         Ok(())
     }
 }
 
+type CommandResult<'a> = BoxFuture<'a, Result<()>>;
+
+/// A chat command registered with the dispatcher: a `keyword` matched
+/// against the first whitespace-separated token of a command message, a
+/// minimum `arity` of remaining arguments, and a handler to run once both
+/// match.
+struct Command {
+    keyword: &'static str,
+    arity: usize,
+    exec: for<'a> fn(&'a App, &'a Client, &'a ChatCommand<&'a str>, &'a [&'a str])
+        -> CommandResult<'a>,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        keyword: "help",
+        arity: 0,
+        exec: |app, client, command, _args| cmd_help(app, client, command).boxed(),
+    },
+    Command {
+        keyword: "stats",
+        arity: 0,
+        exec: |app, client, command, _args| cmd_stats(app, client, command).boxed(),
+    },
+    Command {
+        keyword: "seen",
+        arity: 1,
+        exec: |app, client, command, args| cmd_seen(app, client, command, args).boxed(),
+    },
+    Command {
+        keyword: "reconnect",
+        arity: 0,
+        exec: |app, client, command, _args| cmd_reconnect(app, client, command).boxed(),
+    },
+];
+
+async fn cmd_help(app: &App, client: &Client, command: &ChatCommand<&str>)
+        -> Result<()> {
+    trace!("command_help");
+    app.reply(client, command, &app.help_msg)
+}
+
+async fn cmd_stats(app: &App, client: &Client, command: &ChatCommand<&str>)
+        -> Result<()> {
+    trace!("command_stats");
+    let tweets: i64 = app.db.query_row("
+        select count(*) from tweet
+    ", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let lines: i64 = app.db.query_row("
+        select count(*) from line
+    ", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let occurences: i64 = app.db.query_row("
+        select count(*) from occurence
+    ", rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+    app.reply(client, command, &format!(
+        "{} tweets, {} lines, {} occurences", tweets, lines, occurences))
+}
+
+async fn cmd_seen(app: &App, client: &Client, command: &ChatCommand<&str>,
+        args: &[&str]) -> Result<()> {
+    trace!(?args, "command_seen");
+    let query = args[0];
+    let tweet_id = get_tweet(query).or_else(|| query.parse().ok());
+    let tweet_id = match tweet_id {
+        Some(tweet_id) => tweet_id,
+        None => {
+            return app.reply(client, command,
+                &format!("that doesn't look like a tweet url or id: {}", query));
+        }
+    };
+
+    let seen: Option<String> = app.db.query_row("
+        select line.timestamp
+        from tweet
+        join occurence on occurence.tweet = tweet.id
+        join line on line.id = occurence.line
+        where tweet.tweet_id = ?
+        order by line.timestamp asc
+        limit 1
+    ", &[tweet_id as i64], |row| row.get(0)).optional()?;
+
+    app.reply(client, command, &match seen {
+        Some(timestamp) => format!("first seen on {}", timestamp),
+        None => format!("never seen"),
+    })
+}
+
+async fn cmd_reconnect(app: &App, client: &Client, command: &ChatCommand<&str>)
+        -> Result<()> {
+    trace!("command_reconnect");
+    app.reconnect_requested.store(true, Ordering::SeqCst);
+    app.reply(client, command, "reconnecting...")
+}
+
 #[derive(Debug)]
 struct ChatCommand<S: AsRef<str>+Debug> {
     message: S,
@@ -248,6 +527,23 @@ fn get_tweet(url_str: &str) -> Option<u64> {
     Some(tweet_id)
 }
 
+fn format_preview(author: &str, text: &str) -> String {
+    const MAX_LEN: usize = 400;
+
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut preview = format!("@{}: {}", author, collapsed);
+    if preview.len() > MAX_LEN {
+        let mut cut = MAX_LEN - 1;
+        while !preview.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        preview.truncate(cut);
+        preview.push('…');
+    }
+
+    preview
+}
+
 fn r<T>(r: irc::error::Result<T>) -> Result<T> {
     match r {
         Ok(v) => Ok(v),
@@ -274,25 +570,78 @@ fn main(args: Args) -> Result<()> {
 
     let (irc_config, help_msg) = (config.irc, config.help_msg);
 
-    let app = App { db, link_finder, help_msg };
+    let token = if config.unfurl {
+        let twitter = config.twitter.ok_or_else(||
+            anyhow!("unfurl is enabled but no [twitter] config was given"))?;
+        Some(egg_mode::Token::Access {
+            consumer: egg_mode::KeyPair::new(twitter.consumer_token,
+                twitter.consumer_token_secret),
+            access: egg_mode::KeyPair::new(twitter.access_token,
+                twitter.access_token_secret),
+        })
+    } else {
+        None
+    };
+
+    let app = App {
+        db, link_finder, help_msg, token,
+        reconnect_requested: AtomicBool::new(false),
+        announce_reposts: config.announce_reposts,
+        repost_last_announced: std::sync::Mutex::new(std::collections::HashMap::new()),
+    };
 
     app.init_db()?;
 
     Runtime::new()?.block_on(async {
-        let mut client = r(Client::from_config(irc_config).await)?;
-        r(client.identify())?;
-        let mut stream = r(client.stream())?;
-        while let Some(message) = r(stream.next().await.transpose())? {
-            let span = span!(Level::TRACE, "message", %message);
-            let _enter = span.enter();
-            if let Err(e) = app.handle_message(&client, &message).await {
-                error!(%e, %message, "couldn't handle message");
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+
+        loop {
+            let connected_at = Instant::now();
+
+            if let Err(e) = run_connection(&app, irc_config.clone()).await {
+                error!(%e, "connection lost");
             }
+
+            if connected_at.elapsed() >= SUSTAINED_CONNECTION {
+                backoff = MIN_RECONNECT_BACKOFF;
+            }
+
+            info!(?backoff, "reconnecting");
+            delay_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_RECONNECT_BACKOFF);
         }
 
+        #[allow(unreachable_code)]
         Ok(()): Result<()>
     })?;
 
 
+    Ok(())
+}
+
+/// Runs a single IRC connection to completion: connects, identifies,
+/// and processes messages until the stream ends, the connection drops,
+/// or a `reconnect` command asks us to redial.
+async fn run_connection(app: &App, irc_config: irc::client::data::config::Config)
+        -> Result<()> {
+    let mut client = r(Client::from_config(irc_config).await)?;
+    r(client.identify())?;
+    let mut stream = r(client.stream())?;
+
+    app.reconnect_requested.store(false, Ordering::SeqCst);
+
+    while let Some(message) = r(stream.next().await.transpose())? {
+        let span = span!(Level::TRACE, "message", %message);
+        let _enter = span.enter();
+        if let Err(e) = app.handle_message(&client, &message).await {
+            error!(%e, %message, "couldn't handle message");
+        }
+
+        if app.reconnect_requested.swap(false, Ordering::SeqCst) {
+            info!("reconnect requested, dropping connection");
+            break;
+        }
+    }
+
     Ok(())
 }
@@ -5,7 +5,7 @@ use {
         path::{Path, PathBuf},
         fs,
         io,
-        time,
+        time::{self, SystemTime, UNIX_EPOCH},
     },
     anyhow::Result,
     rusqlite::types::ToSql,
@@ -57,14 +57,43 @@ impl App {
             )
         ", rusqlite::NO_PARAMS)?;
 
+        self.add_column_if_missing("tweet", "retry_after", "integer")?;
+        self.add_column_if_missing("tweet", "attempts", "integer not null default 0")?;
+
         Ok(())
     }
 
-    fn get_new_tweets(&self, limit: i32) -> Result<Vec<u64>> {
+    fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        let mut stmt = self.db.prepare(&format!("pragma table_info({})", table))?;
+        let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(1)?;
+            if name == column {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn add_column_if_missing(&self, table: &str, column: &str, ty: &str)
+            -> Result<()> {
+        if !self.has_column(table, column)? {
+            self.db.execute(&format!(
+                "alter table {} add column {} {}", table, column, ty
+            ), rusqlite::NO_PARAMS)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_new_tweets(&self, limit: i32) -> Result<Vec<(u64, i32)>> {
         let mut stmt = self.db.prepare("
-            select tweet_id
+            select tweet_id, attempts
             from tweet
             where retweet_id is null and error is null
+                and (retry_after is null or retry_after <= strftime('%s', 'now'))
+            order by tweet_id
             limit ?;
         ")?;
 
@@ -72,7 +101,8 @@ impl App {
         let mut ids = Vec::new();
         while let Some(row) = rows.next()? {
             let id: i64 = row.get(0)?;
-            ids.push(id as u64);
+            let attempts: i32 = row.get(1)?;
+            ids.push((id as u64, attempts));
         }
 
         Ok(ids)
@@ -103,14 +133,26 @@ impl App {
         Ok(())
     }
 
+    fn store_retry_after(&self, tweet_id: u64, attempts: i32, retry_after: i64)
+            -> Result<()> {
+        let tweet_id = tweet_id as i64;
+        self.db.execute("
+            update tweet
+            set retry_after = ?, attempts = ?
+            where tweet_id is ? and retweet_id is null and error is null
+        ", &[&retry_after, &(attempts + 1) as &dyn ToSql, &tweet_id]: &[&dyn ToSql; 3])?;
+
+        Ok(())
+    }
+
     async fn tick(&self) -> Result<()> {
-        let tweet_ids = self.get_new_tweets(100)?;
-        if tweet_ids.len() == 0 {
+        let tweets = self.get_new_tweets(100)?;
+        if tweets.len() == 0 {
             return Ok(());
         }
 
-        for tweet_id in tweet_ids {
-            let span = span!(Level::INFO, "processing tweet", %tweet_id);
+        for (tweet_id, attempts) in tweets {
+            let span = span!(Level::INFO, "processing tweet", %tweet_id, %attempts);
             let _enter = span.enter();
             let result =
                 egg_mode::tweet::retweet(tweet_id, &self.creds).await;
@@ -119,9 +161,23 @@ impl App {
                     let retweet = r.response;
                     info!(%retweet.id, "retweeted");
                     self.store_retweet_id(tweet_id, retweet.id)?;
-                }, Err(e) => {
-                    error!(%e, "couldn't retweet");
-                    self.store_error(tweet_id, e.to_string())?;
+                },
+                Err(e) => match classify_error(&e) {
+                    ErrorClass::Permanent => {
+                        error!(%e, "tweet is gone for good, giving up");
+                        self.store_error(tweet_id, e.to_string())?;
+                    },
+                    ErrorClass::RateLimited { reset } => {
+                        error!(%e, %reset, "rate limited, sleeping until reset");
+                        self.store_retry_after(tweet_id, attempts, reset)?;
+                        let wait = (reset - now()).max(0) as u64;
+                        delay_for(time::Duration::from_secs(wait)).await;
+                    },
+                    ErrorClass::Transient => {
+                        let retry_after = now() + backoff_secs(attempts);
+                        error!(%e, %retry_after, "transient error, will retry later");
+                        self.store_retry_after(tweet_id, attempts, retry_after)?;
+                    },
                 },
             }
         }
@@ -140,6 +196,41 @@ impl App {
     }
 }
 
+enum ErrorClass {
+    /// Will never succeed; stop retrying and record the error.
+    Permanent,
+    /// Rate limited until the unix time `reset`: the reset Twitter reported,
+    /// or one minute from now for a bare HTTP 429 that carries none.
+    RateLimited { reset: i64 },
+    /// Worth trying again later; back off and retry.
+    Transient,
+}
+
+fn classify_error(error: &egg_mode::error::Error) -> ErrorClass {
+    use egg_mode::error::Error;
+
+    match error {
+        Error::RateLimit(reset) => ErrorClass::RateLimited { reset: *reset as i64 },
+        Error::BadStatus(status) if status.as_u16() == 429 =>
+            ErrorClass::RateLimited { reset: now() + 60 },
+        Error::BadStatus(status)
+                if status.as_u16() == 404 || status.as_u16() == 403 =>
+            ErrorClass::Permanent,
+        _ => ErrorClass::Transient,
+    }
+}
+
+fn backoff_secs(attempts: i32) -> i64 {
+    let exp = if attempts < 0 { 0 } else if attempts > 10 { 10 } else { attempts };
+    std::cmp::min(300, 5i64 * 2i64.pow(exp as u32))
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("system clock before unix epoch")
+        .as_secs() as i64
+}
+
 #[paw::main]
 #[tokio::main]
 async fn main(args: Args) -> Result<()> {